@@ -21,9 +21,99 @@ pub trait DirectAccessBuf {
     }
 }
 
+///Compression pointers are only followed this many times before `read_qname` gives up, in case a
+///packet is crafted to bounce between several pointers rather than looping on a single one.
+pub const MAX_POINTER_JUMPS: u8 = 32;
+
+///Cap on the total assembled name length (RFC 1035 2.3.4), enforced while following compression
+///pointers so a crafted packet can't make `read_qname` assemble an unbounded name.
+pub const MAX_NAME_LENGTH: usize = 255;
+
+#[derive(Debug)]
+pub enum QnameError {
+    TooManyJumps,
+    InvalidJumpTarget,
+    NameTooLong,
+}
+
 pub trait BufRead : DirectAccessBuf {
     fn buf(&self) -> &[u8];
 
+    ///Reads a (possibly compressed) qname starting at the current position, following
+    ///compression pointers (a length byte with its top two bits set, RFC 1035 4.1.4) as needed.
+    ///A pointer must always jump strictly backwards from where it was read, and jumps are capped
+    ///at `MAX_POINTER_JUMPS`, so a crafted packet that points back on itself errors out here
+    ///instead of looping or expanding forever.
+    fn read_qname(&mut self) -> Result<String, QnameError> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut total_len = 0usize;
+        let mut jumps = 0u8;
+        let mut jumped = false;
+        let mut return_pos = self.pos();
+
+        loop {
+            let pointer_pos = self.pos();
+            let len_byte = match self.peek_u8() {
+                Some(b) => b,
+                None => return Err(QnameError::InvalidJumpTarget),
+            };
+
+            if len_byte & 0xC0 == 0xC0 {
+                let raw = match self.next_u16() {
+                    Some(v) => v,
+                    None => return Err(QnameError::InvalidJumpTarget),
+                };
+                let target = (raw & 0x3FFF) as usize;
+
+                if target >= pointer_pos {
+                    return Err(QnameError::InvalidJumpTarget);
+                }
+
+                jumps += 1;
+                if jumps > MAX_POINTER_JUMPS {
+                    return Err(QnameError::TooManyJumps);
+                }
+
+                if !jumped {
+                    return_pos = self.pos();
+                    jumped = true;
+                }
+
+                if !self.seek(target) {
+                    return Err(QnameError::InvalidJumpTarget);
+                }
+                continue;
+            }
+
+            self.advance(1);
+            if len_byte == 0 {
+                break;
+            }
+
+            let label_len = len_byte as usize;
+            total_len += label_len + 1;
+            if total_len > MAX_NAME_LENGTH {
+                return Err(QnameError::NameTooLong);
+            }
+
+            let label_bytes = self.next_bytes(label_len);
+            if label_bytes.len() != label_len {
+                return Err(QnameError::InvalidJumpTarget);
+            }
+
+            match String::from_utf8(label_bytes) {
+                Ok(label) => labels.push(label),
+                Err(_) => return Err(QnameError::InvalidJumpTarget),
+            }
+        }
+
+        if jumped {
+            self.seek(return_pos);
+        }
+
+        Ok(labels.join("."))
+    }
+
     fn peek_u8(&self) -> Option<u8> {
         if self.pos() >= self.len() {
             return None;
@@ -130,4 +220,104 @@ pub trait BufWrite : BufRead {
         }
         true
     }
+}
+
+///A minimal `BufRead` over a borrowed byte slice, for callers that just need to read a qname (or
+///anything else `BufRead` offers) out of some bytes they already have - e.g. rdata - without a
+///full `DnsMessage` parse.
+pub struct SliceBuf<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> SliceBuf<'a> {
+    pub fn new(data: &'a [u8]) -> SliceBuf<'a> {
+        SliceBuf {
+            data: data,
+            pos: 0
+        }
+    }
+}
+
+impl<'a> DirectAccessBuf for SliceBuf<'a> {
+    fn pos(&self) -> usize {
+        self.pos
+    }
+    fn set_pos(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+    fn len(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl<'a> BufRead for SliceBuf<'a> {
+    fn buf(&self) -> &[u8] {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BufRead, DirectAccessBuf, SliceBuf, QnameError};
+
+    #[test]
+    fn read_qname_reads_uncompressed_name() {
+        let msg = [0x03, b'f', b'o', b'o', 0x03, b'c', b'o', b'm', 0x00];
+        let mut buf = SliceBuf::new(&msg);
+        assert_eq!("foo.com", buf.read_qname().unwrap());
+    }
+
+    #[test]
+    fn read_qname_follows_a_single_pointer() {
+        // "com" at offset 0, then a name at offset 5 that points back to it.
+        let msg = [0x03, b'c', b'o', b'm', 0x00, 0xC0, 0x00];
+        let mut buf = SliceBuf::new(&msg);
+        buf.seek(5);
+        assert_eq!("com", buf.read_qname().unwrap());
+    }
+
+    #[test]
+    fn read_qname_errors_on_a_pointer_that_points_at_itself() {
+        // A 2-byte pointer at offset 0 pointing back at offset 0: an infinite loop if followed
+        // naively.
+        let msg = [0xC0, 0x00];
+        let mut buf = SliceBuf::new(&msg);
+        assert!(buf.read_qname().is_err());
+    }
+
+    #[test]
+    fn read_qname_errors_on_a_pointer_that_points_forward() {
+        // Pointer at offset 0 targeting offset 2, which is ahead of where it was read from.
+        let msg = [0xC0, 0x02, 0x00];
+        let mut buf = SliceBuf::new(&msg);
+        match buf.read_qname() {
+            Err(QnameError::InvalidJumpTarget) => {}
+            other => panic!("expected InvalidJumpTarget, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_qname_errors_on_a_chain_of_pointers_exceeding_the_jump_cap() {
+        // Each 2-byte pointer points at the position of the previous one, forming a strictly
+        // backwards-jumping chain - legal under the "strictly less than" rule one jump at a
+        // time, but long enough (40 hops) to blow through MAX_POINTER_JUMPS (32).
+        let mut msg = vec![0x00]; // offset 0: root label, a valid terminator if ever reached
+        let mut target: u16 = 0;
+        let mut last_pointer_pos: u16 = 0;
+        for _ in 0..40 {
+            let here = msg.len() as u16;
+            msg.push(0xC0 | ((target >> 8) as u8));
+            msg.push(target as u8);
+            target = here;
+            last_pointer_pos = here;
+        }
+
+        let mut buf = SliceBuf::new(&msg);
+        buf.seek(last_pointer_pos as usize);
+        match buf.read_qname() {
+            Err(QnameError::TooManyJumps) => {}
+            other => panic!("expected TooManyJumps, got {:?}", other),
+        }
+    }
 }
\ No newline at end of file
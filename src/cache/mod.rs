@@ -1,25 +1,32 @@
 extern crate time;
 
+use std::cell::Cell;
 use std::collections::{HashMap};
 use std::cmp::Ordering;
 use time::*;
 use dns::message::*;
+use buf::{BufRead, DirectAccessBuf};
 
+///Default cap on the number of entries held in a `Cache` built with `Cache::default`.
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
 
-///Unbounded cache of DnsAnswer
+///RR type of an SOA record, used to find the minimum TTL to apply to negative cache entries.
+const SOA_TYPE: u16 = 6;
+
+///Bounded cache of DnsAnswer.
 ///It tries to be somewhat performant by using a HashMap for lookups and keeping
-///an ordered Vec of keys by expiry for fast removal of expired items. 
+///an ordered Vec of keys by expiry for fast removal of expired items.
+///Once `max_entries` is reached, the least-recently-used entry (by `CacheEntry::last_access`)
+///is evicted to make room, similar to the `lru_time_cache` used by the dns-resolver crate.
 pub struct Cache {
     map: HashMap<CacheKey,CacheEntry>, //for retrieval
-    keys: Vec<CacheExpiry> //for expiring (ordered). BTreeSet/Map doesn't work because it does't have any way to iterate and remove
+    keys: Vec<CacheExpiry>, //for expiring (ordered). BTreeSet/Map doesn't work because it does't have any way to iterate and remove
+    max_entries: usize
 }
 
 impl Default for Cache  {
     fn default() -> Cache {
-        Cache {
-            map: HashMap::new(),
-            keys: Vec::new()
-        }
+        Cache::new(DEFAULT_MAX_ENTRIES)
     }
 }
 
@@ -28,18 +35,45 @@ pub trait Expires {
 }
 
 impl Cache  {
+    pub fn new(max_entries: usize) -> Cache {
+        Cache {
+            map: HashMap::new(),
+            keys: Vec::new(),
+            max_entries: max_entries
+        }
+    }
+
     pub fn upsert(&mut self, key: CacheKey, val: CacheEntry) {
         self.remove_expired();
+        if !self.map.contains_key(&key) && self.map.len() >= self.max_entries {
+            self.evict_lru();
+        }
         let expiry_data = CacheExpiry::new(key.clone(), val.expiry());
         debug!("Cached answer with key {:?}", key);
         self.keys.insert(0, expiry_data);
-        self.keys.sort(); //only 1 item should ever be out-of-order. 
-        self.map.entry(key).or_insert(val);        
+        self.keys.sort(); //only 1 item should ever be out-of-order.
+        self.map.entry(key).or_insert(val);
         debug!("There are {} keys and {} map entries", self.keys.len(), self.map.len());
     }
 
-    pub fn get(&self, key: &CacheKey) -> Option<&CacheEntry> {
-        self.map.get(key)
+    pub fn get(&self, key: &CacheKey) -> Option<CacheEntry> {
+        self.map.get(key).map(|found| {
+            found.touch();
+            found.with_remaining_ttl()
+        })
+    }
+
+    ///Evict the entry that was least-recently read (via `get`) to make room for a new one.
+    fn evict_lru(&mut self) {
+        let lru_key = self.map.iter()
+            .min_by_key(|&(_, entry)| entry.last_access.get())
+            .map(|(key, _)| key.clone());
+
+        if let Some(key) = lru_key {
+            debug!("Evicting LRU entry {:?}", key);
+            self.map.remove(&key);
+            self.keys.retain(|cache_expiry| cache_expiry.key != key);
+        }
     }
 
     #[allow(dead_code)]
@@ -108,7 +142,13 @@ impl CacheKey {
 
 impl Ord for CacheKey {
     fn cmp(&self, other: &Self) -> Ordering {
-        self.qname.cmp(&other.qname)
+        match self.qname.cmp(&other.qname) {
+            Ordering::Equal => match self.qtype.cmp(&other.qtype) {
+                Ordering::Equal => self.qclass.cmp(&other.qclass),
+                ordering => ordering
+            },
+            ordering => ordering
+        }
     }
 }
 
@@ -116,31 +156,62 @@ impl Ord for CacheKey {
 #[derive(PartialEq)]
 #[derive(Eq)]
 #[derive(Debug)]
+#[derive(Clone)]
 pub struct CacheEntry {
     pub key: CacheKey, //for expiring
     pub answers: Vec<DnsAnswer>,
+    pub negative: bool, //true for a cached NXDOMAIN/NODATA, so lookups can synthesize the rcode
+    pub rcode: u8, //the original response's RCODE (RFC 1035 4.1.1) - NXDOMAIN, or NOERROR for a NODATA or positive entry
     ttl: u32,
-    expiry: SteadyTime
+    expiry: SteadyTime,
+    last_access: Cell<SteadyTime> //bumped by Cache::get, read by Cache::evict_lru
 }
 
 impl CacheEntry {
-    pub fn new(key: CacheKey, answers: Vec<DnsAnswer>, ttl: u32) -> CacheEntry {
+    pub fn new(key: CacheKey, answers: Vec<DnsAnswer>, ttl: u32, negative: bool, rcode: u8) -> CacheEntry {
+        let now = SteadyTime::now();
         CacheEntry {
             key: key,
             answers: answers,
+            negative: negative,
+            rcode: rcode,
             ttl: ttl,
-            expiry: SteadyTime::now() + Duration::seconds(ttl as i64)
+            expiry: now + Duration::seconds(ttl as i64),
+            last_access: Cell::new(now)
         }
     }
 
-    pub fn from(msg: &DnsMessage) -> Option<CacheEntry> {        
-        if let Some(answer) = msg.first_answer() {
-            let a = answer.clone();
-            let key = CacheKey::new(a.name.to_string(), a.atype, a.aclass);
-            return Some(CacheEntry::new(key, msg.clone().answers, answer.ttl))
-        } else {
-            warn!("No answer in {:?}", msg);
+    fn touch(&self) {
+        self.last_access.set(SteadyTime::now());
+    }
+
+    ///Returns a copy of this entry with every answer's `ttl` rewritten to its remaining
+    ///lifetime, so repeated lookups of a long-lived entry show a counting-down TTL rather than
+    ///the original one it was cached with.
+    fn with_remaining_ttl(&self) -> CacheEntry {
+        let remaining = self.calc_ttl();
+        let mut entry = self.clone();
+        for answer in entry.answers.iter_mut() {
+            answer.ttl = remaining;
+        }
+        entry
+    }
+
+    pub fn from(msg: &DnsMessage) -> Option<CacheEntry> {
+        if msg.first_answer().is_some() {
+            let answers = msg.clone().answers;
+            let ttl = answers.iter().map(|answer| answer.ttl).min().unwrap_or(0);
+            let key = CacheKey::from(&msg.question);
+            return Some(CacheEntry::new(key, answers, ttl, false, msg.rcode))
+        }
+
+        if let Some(ttl) = soa_minimum_ttl(&msg.authority) {
+            let key = CacheKey::from(&msg.question);
+            debug!("Caching negative response for {:?} with ttl {} (rcode {})", key, ttl, msg.rcode);
+            return Some(CacheEntry::new(key, Vec::new(), ttl, true, msg.rcode))
         }
+
+        warn!("No answer in {:?}", msg);
         None
     }
 
@@ -166,6 +237,36 @@ impl Expires for CacheEntry {
     }
 }
 
+///The last 4 bytes of an SOA record's rdata are the MINIMUM field (RFC 1035 3.3.13), which
+///negative responses should be cached against.
+fn soa_minimum_ttl(authority: &[DnsAnswer]) -> Option<u32> {
+    authority.iter()
+        .find(|rr| rr.atype == SOA_TYPE)
+        .and_then(|soa| {
+            let len = soa.rdata.len();
+            if len < 4 {
+                return None;
+            }
+            let mut buf = RdataBuf { data: &soa.rdata, pos: len - 4 };
+            buf.next_u32()
+        })
+}
+
+struct RdataBuf<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> DirectAccessBuf for RdataBuf<'a> {
+    fn pos(&self) -> usize { self.pos }
+    fn set_pos(&mut self, pos: usize) { self.pos = pos; }
+    fn len(&self) -> usize { self.data.len() }
+}
+
+impl<'a> BufRead for RdataBuf<'a> {
+    fn buf(&self) -> &[u8] { self.data }
+}
+
 #[derive(PartialOrd)]
 #[derive(PartialEq)]
 #[derive(Eq)]
@@ -201,7 +302,7 @@ mod test {
     fn test_cache() -> Cache {
         let mut cache = Cache::default();
         let key = CacheKey::new(String::from("yahoo.com"), 1, 1);
-        let val = CacheEntry::new(key.clone(), test_answers(), 5);
+        let val = CacheEntry::new(key.clone(), test_answers(), 5, false, 0);
         cache.upsert(key.clone(), val);
         cache
     }
@@ -241,7 +342,7 @@ mod test {
     fn expiry() {
         let mut cache = test_cache();
         let key2 = CacheKey::new(String::from("lycos.com"), 1, 1);
-        let val2 = CacheEntry::new(key2.clone(), test_answers_with(String::from("lycos.com")), 1);
+        let val2 = CacheEntry::new(key2.clone(), test_answers_with(String::from("lycos.com")), 1, false, 0);
         cache.upsert(key2, val2);
 
         assert_eq!(2, cache.len());
@@ -262,4 +363,36 @@ mod test {
         let key = CacheKey::new(String::from("yahoo.com"), 1, 1);
         assert!(cache.contains(&key));
     }
+
+    #[test]
+    fn evicts_the_least_recently_used_entry_once_max_entries_is_reached() {
+        let mut cache = Cache::new(2);
+        let key_a = test_key_with(String::from("a.com"));
+        let key_b = test_key_with(String::from("b.com"));
+        let key_c = test_key_with(String::from("c.com"));
+
+        cache.upsert(key_a.clone(), CacheEntry::new(key_a.clone(), test_answers_with(String::from("a.com")), 5, false, 0));
+        cache.upsert(key_b.clone(), CacheEntry::new(key_b.clone(), test_answers_with(String::from("b.com")), 5, false, 0));
+
+        // Touch a.com so b.com becomes the least-recently-used entry.
+        assert!(cache.get(&key_a).is_some());
+
+        cache.upsert(key_c.clone(), CacheEntry::new(key_c.clone(), test_answers_with(String::from("c.com")), 5, false, 0));
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.contains(&key_a));
+        assert!(!cache.contains(&key_b));
+        assert!(cache.contains(&key_c));
+    }
+
+    #[test]
+    fn get_returns_a_negative_entry_with_no_answers() {
+        let mut cache = Cache::default();
+        let key = test_key_with(String::from("nxdomain.com"));
+        cache.upsert(key.clone(), CacheEntry::new(key.clone(), Vec::new(), 5, true, 3));
+
+        let found = cache.get(&key).unwrap();
+        assert!(found.negative);
+        assert!(found.answers.is_empty());
+    }
 }
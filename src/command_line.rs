@@ -0,0 +1,143 @@
+use getopts::Options;
+use std::cell::RefCell;
+use std::env;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::str::FromStr;
+use cache::Cache;
+use resolver::ResolverMode;
+
+const DEFAULT_LISTEN_ADDR: &'static str = "0.0.0.0:53";
+const DEFAULT_UPSTREAM_ADDR: &'static str = "8.8.8.8:53";
+
+///Parsed command-line configuration for a single run of the server.
+pub struct Config {
+    pub listen_addr: SocketAddr,
+    pub upstream_addr: SocketAddr,
+    pub cache_max_entries: usize,
+    pub resolver_mode: ResolverMode,
+}
+
+impl Config {
+    ///Builds the one `Cache` the server should run with, shared (via `Rc<RefCell<_>>`, the event
+    ///loop being single-threaded) into every `RequestParams` so a lookup made while resolving one
+    ///request can be reused by the next, rather than each request walking from the root cold.
+    pub fn new_cache(&self) -> Rc<RefCell<Cache>> {
+        Rc::new(RefCell::new(Cache::new(self.cache_max_entries)))
+    }
+}
+
+fn options() -> Options {
+    let mut opts = Options::new();
+    opts.optopt("l", "listen", "address to listen on", "ADDR:PORT");
+    opts.optopt("u", "upstream", "upstream server to forward to", "ADDR:PORT");
+    opts.optopt("c",
+                "cache-max-entries",
+                "maximum number of entries to hold in the cache before evicting the \
+                 least-recently-used one",
+                "N");
+    opts.optflag("r",
+                 "recursive",
+                 "resolve queries recursively from the root instead of forwarding them to \
+                  --upstream");
+    opts.optflag("h", "help", "print this help menu");
+    opts
+}
+
+///Parse `args` (typically `env::args().skip(1)`) into a `Config`, falling back to this module's
+///defaults for anything not passed on the command line.
+pub fn parse(args: &[String]) -> Option<Config> {
+    let opts = options();
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(e) => {
+            println!("{}", e);
+            return None;
+        }
+    };
+
+    if matches.opt_present("h") {
+        println!("{}", opts.usage("Usage: koala-dns [options]"));
+        return None;
+    }
+
+    let listen = matches.opt_str("l").unwrap_or(DEFAULT_LISTEN_ADDR.to_string());
+    let upstream = matches.opt_str("u").unwrap_or(DEFAULT_UPSTREAM_ADDR.to_string());
+    let cache_max_entries = matches.opt_str("c")
+        .and_then(|raw| raw.parse().ok())
+        .unwrap_or(::cache::DEFAULT_MAX_ENTRIES);
+    let resolver_mode = if matches.opt_present("r") {
+        ResolverMode::Recursive
+    } else {
+        ResolverMode::Forward
+    };
+
+    let listen_addr = match SocketAddr::from_str(&listen) {
+        Ok(addr) => addr,
+        Err(e) => {
+            println!("Invalid listen address {:?}: {:?}", listen, e);
+            return None;
+        }
+    };
+
+    let upstream_addr = match SocketAddr::from_str(&upstream) {
+        Ok(addr) => addr,
+        Err(e) => {
+            println!("Invalid upstream address {:?}: {:?}", upstream, e);
+            return None;
+        }
+    };
+
+    Some(Config {
+        listen_addr: listen_addr,
+        upstream_addr: upstream_addr,
+        cache_max_entries: cache_max_entries,
+        resolver_mode: resolver_mode,
+    })
+}
+
+///Parse the real process arguments (`env::args()`, minus the program name) via `parse`: on
+///`--help` or a parse error the usage or error is printed and `None` is returned; the process is
+///not exited.
+pub fn parse_args() -> Option<Config> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    parse(&args)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse;
+    use resolver::ResolverMode;
+
+    #[test]
+    fn defaults_cache_max_entries_when_not_passed() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.cache_max_entries, ::cache::DEFAULT_MAX_ENTRIES);
+    }
+
+    #[test]
+    fn defaults_to_forwarding_mode() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.resolver_mode, ResolverMode::Forward);
+    }
+
+    #[test]
+    fn selects_recursive_mode_when_passed() {
+        let args = vec!["--recursive".to_string()];
+        let config = parse(&args).unwrap();
+        assert_eq!(config.resolver_mode, ResolverMode::Recursive);
+    }
+
+    #[test]
+    fn parses_cache_max_entries() {
+        let args = vec!["--cache-max-entries".to_string(), "42".to_string()];
+        let config = parse(&args).unwrap();
+        assert_eq!(config.cache_max_entries, 42);
+    }
+
+    #[test]
+    fn rejects_an_invalid_listen_address() {
+        let args = vec!["--listen".to_string(), "not-an-address".to_string()];
+        assert!(parse(&args).is_none());
+    }
+}
@@ -11,8 +11,10 @@ extern crate test;
 pub mod server;
 
 mod dns;
+mod cache;
 mod command_line;
 mod server_mio;
 mod request;
 mod buf;
 mod socket;
+mod resolver;
@@ -0,0 +1,172 @@
+use std::net::SocketAddr;
+use buf::{BufRead, DirectAccessBuf, SliceBuf};
+use dns::message::DnsMessage;
+use resolver::{RecursiveResolver, ResolverMode, MAX_REFERRALS};
+use request::base::{RequestBase, RequestState};
+
+///Offset of the 16-bit ARCOUNT field in a DNS header (RFC 1035 4.1.1).
+const ARCOUNT_OFFSET: usize = 10;
+
+///Append a root OPT pseudo-RR to `query`'s additional section advertising `payload_size` as the
+///UDP buffer the forwarder can receive over, and bump ARCOUNT to match. A no-op if the query
+///already carries additional records, since we only ever add our own OPT once per forward. Only
+///meaningful for a query that will actually travel as a UDP datagram - it exists so a large
+///upstream response comes back as one datagram instead of truncated, which only UDP can suffer.
+pub fn append_edns0_opt(query: &mut Vec<u8>, payload_size: u16) {
+    if query.len() < 12 {
+        return;
+    }
+
+    let arcount = ((query[ARCOUNT_OFFSET] as u16) << 8) | query[ARCOUNT_OFFSET + 1] as u16;
+    if arcount != 0 {
+        return;
+    }
+
+    query.push(0x00); // NAME: root
+    query.push(0x00); // TYPE: OPT (41), high byte
+    query.push(0x29); // TYPE: OPT (41), low byte
+    query.push((payload_size >> 8) as u8); // CLASS: requestor's UDP payload size
+    query.push(payload_size as u8);
+    query.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]); // TTL: extended-rcode/version/flags, all zero
+    query.push(0x00); // RDLEN
+    query.push(0x00);
+
+    query[ARCOUNT_OFFSET] = 0x00;
+    query[ARCOUNT_OFFSET + 1] = 0x01;
+}
+
+///Decode the qname of the first question in `msg`, following compression pointers safely via
+///`buf::read_qname` rather than assuming an uncompressed name.
+pub fn qname(msg: &[u8]) -> Option<String> {
+    if msg.len() < 13 {
+        return None;
+    }
+
+    let mut buf = SliceBuf::new(msg);
+    if !buf.seek(12) {
+        return None;
+    }
+    buf.read_qname().ok()
+}
+
+///Anti-spoofing: only trust a response that (a) arrived from the upstream address `query` was
+///actually sent to, (b) echoes `query`'s transaction ID, and (c) answers the same question,
+///mirroring the encrypted-dns-server resolver's validation. Shared by both transports - on UDP
+///`from` is whatever `recv_from` reports, which an off-path attacker can forge outright; on TCP
+///it's `peer_addr()` of the socket we `connect`ed, which the OS already ties to the one address
+///we dialed, so the check there is closer to a cheap extra assertion than real protection.
+pub fn is_valid_response(query: &[u8], response: &[u8], from: SocketAddr, expected_upstream: SocketAddr) -> bool {
+    let from_expected_upstream = from == expected_upstream;
+
+    let tid_matches = response.len() >= 2 && query.len() >= 2 && response[0..2] == query[0..2];
+
+    let qname_matches = match (qname(response), qname(query)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    };
+
+    from_expected_upstream && tid_matches && qname_matches
+}
+
+///If `inner.params.resolver_mode` asks for recursive resolution, resolve its query that way
+///instead of forwarding it, buffering whatever `RecursiveResolver` comes back with straight into
+///`inner` and moving it to `ResponseReceived`. Returns `true` when it handled the request this
+///way, so the caller (`TcpRequest`/`UdpRequest::accept`) knows to skip its own upstream dial.
+///Resolves against `inner.params.cache` - the one `Cache` shared by every request on this server
+///(built once by `command_line::Config::new_cache`) - so an answer looked up while resolving one
+///request (including a glueless nameserver's address) is still there for the next. Referrals
+///themselves are not cached.
+pub fn try_resolve_recursively(inner: &mut RequestBase) -> bool {
+    if inner.params.resolver_mode != ResolverMode::Recursive {
+        return false;
+    }
+
+    let query = DnsMessage::parse(&inner.query_buf);
+    let mut cache = inner.params.cache.borrow_mut();
+    match RecursiveResolver::new().resolve_with(&query.question, &mut cache, MAX_REFERRALS) {
+        Some(response) => {
+            let bytes = response.to_bytes();
+            let len = bytes.len();
+            inner.buffer_response(&bytes, len);
+            inner.set_state(RequestState::ResponseReceived);
+        }
+        None => inner.error_with(format!("Recursive resolution failed for {:?}", query.question)),
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::{append_edns0_opt, is_valid_response, ARCOUNT_OFFSET};
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+
+    fn upstream() -> SocketAddr {
+        SocketAddr::from_str("8.8.8.8:53").unwrap()
+    }
+
+    fn spoofer() -> SocketAddr {
+        SocketAddr::from_str("6.6.6.6:53").unwrap()
+    }
+
+    ///TID 0x1234, one question: "foo", A, IN.
+    fn query() -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        msg.extend_from_slice(&[0x03, b'f', b'o', b'o', 0x00, 0x00, 0x01, 0x00, 0x01]);
+        msg
+    }
+
+    #[test]
+    fn accepts_a_matching_response_from_the_expected_upstream() {
+        let q = query();
+        let mut r = q.clone();
+        r[2] = 0x81; // flags: this copy is a response
+        assert!(is_valid_response(&q, &r, upstream(), upstream()));
+    }
+
+    #[test]
+    fn rejects_a_response_from_an_unexpected_address() {
+        let q = query();
+        assert!(!is_valid_response(&q, &q.clone(), spoofer(), upstream()));
+    }
+
+    #[test]
+    fn rejects_a_response_with_the_wrong_transaction_id() {
+        let q = query();
+        let mut r = q.clone();
+        r[0] = 0x99;
+        assert!(!is_valid_response(&q, &r, upstream(), upstream()));
+    }
+
+    #[test]
+    fn rejects_a_response_answering_a_different_question() {
+        let q = query();
+        let mut r = q.clone();
+        r[13] = b'b';
+        r[14] = b'a';
+        r[15] = b'r';
+        assert!(!is_valid_response(&q, &r, upstream(), upstream()));
+    }
+
+    #[test]
+    fn appends_a_root_opt_rr_advertising_the_payload_size_and_bumps_arcount() {
+        let mut q = query();
+        let before_len = q.len();
+
+        append_edns0_opt(&mut q, 4096);
+
+        assert_eq!(&q[before_len..], &[0x00, 0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(&q[ARCOUNT_OFFSET..ARCOUNT_OFFSET + 2], &[0x00, 0x01]);
+    }
+
+    #[test]
+    fn does_not_append_a_second_opt_rr_when_one_is_already_present() {
+        let mut q = query();
+        append_edns0_opt(&mut q, 4096);
+        let once = q.clone();
+
+        append_edns0_opt(&mut q, 4096);
+
+        assert_eq!(q, once);
+    }
+}
@@ -1,4 +1,4 @@
-use mio::EventSet;
+use mio::{EventSet, TryRead, TryWrite};
 use mio::tcp::{TcpStream, TcpListener};
 use std::net::SocketAddr;
 use request::base::{RequestState, RequestBase, RequestParams};
@@ -6,11 +6,20 @@ use std::collections::HashMap;
 //use dns::dns_entities::DnsMessage;
 use server_mio::RequestContext;
 use request::base::IRequest;
+use request::common;
 
+///Messages sent over TCP are prefixed with a 2-byte big-endian length (RFC 1035 4.2.2), so
+///reads and writes here have to track how much of that framed message has been moved so far.
 pub struct TcpRequest {
     upstream_socket: Option<TcpStream>,
     pub client_addr: SocketAddr,
     pub inner: RequestBase,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    read_buf: Vec<u8>,
+    expected_len: Option<u16>,
+    send_buf: Vec<u8>,
+    send_pos: usize,
 }
 
 impl IRequest<TcpRequest> for TcpRequest {
@@ -19,6 +28,12 @@ impl IRequest<TcpRequest> for TcpRequest {
             upstream_socket: None,
             client_addr: client_addr,
             inner: request,
+            write_buf: Vec::new(),
+            write_pos: 0,
+            read_buf: Vec::new(),
+            expected_len: None,
+            send_buf: Vec::new(),
+            send_pos: 0,
         };
     }
 
@@ -29,18 +44,28 @@ impl IRequest<TcpRequest> for TcpRequest {
 
 impl TcpRequest {
 
-
     fn accept(&mut self, ctx: &mut RequestContext) {
-        // debug_assert!(ctx.events.is_readable());
-        // self.inner.set_state(RequestState::Accepted);
-        // //todo: if need to forward...
-        //
-        // self.upstream_socket = UdpSocket::v4().ok();
-        // debug!("upstream created");
-        // match self.upstream_socket {
-        //     Some(ref sock) => self.inner.register_upstream(ctx, EventSet::writable(), sock),
-        //     None => error!("No upstream socket")
-        // }
+        self.inner.set_state(RequestState::Accepted);
+
+        if common::try_resolve_recursively(&mut self.inner) {
+            return;
+        }
+
+        match TcpStream::connect(&self.inner.params.upstream_addr) {
+            Ok(sock) => {
+                debug!("upstream tcp connection created to {:?}", self.inner.params.upstream_addr);
+                self.upstream_socket = Some(sock);
+                match self.upstream_socket {
+                    Some(ref sock) => self.inner.register_upstream(ctx, EventSet::writable(), sock),
+                    None => error!("No upstream socket"),
+                }
+            }
+            Err(e) => {
+                self.inner.error_with(format!("Failed to connect to upstream {:?}. {:?}",
+                                              self.inner.params.upstream_addr,
+                                              e))
+            }
+        }
     }
 
     pub fn ready(&mut self, ctx: &mut RequestContext) {
@@ -48,75 +73,165 @@ impl TcpRequest {
                self.inner.state,
                ctx.token,
                ctx.events);
-        // todo: authorative? cached? forward?
-        // match self.inner.state {
-        //     RequestState::New => self.accept(ctx),
-        //     RequestState::Accepted => self.forward(ctx),
-        //     RequestState::Forwarded => self.receive(ctx),
-        //     _ => debug!("Nothing to do for this state {:?}", self.inner.state),
-        // }
+        match self.inner.state {
+            RequestState::New => self.accept(ctx),
+            RequestState::Accepted => self.forward(ctx),
+            RequestState::Forwarded => self.receive(ctx),
+            _ => debug!("Nothing to do for this state {:?}", self.inner.state),
+        }
     }
+
     fn forward(&mut self, ctx: &mut RequestContext) {
-        // debug!("Forwarding...");
-        // debug_assert!(ctx.events.is_writable());
-        // //TODO: error on fail to create upstream socket
-        // match self.upstream_socket {
-        //     Some(ref sock) => {
-        //         match sock.send_to(&mut self.inner.query_buf.as_slice(), &self.inner.params.upstream_addr) {
-        //               Ok(Some(_)) => {
-        //                   self.inner.set_state(RequestState::Forwarded);
-        //                   self.inner.register_upstream(ctx, EventSet::readable(), sock);
-        //                   // TODO: No, don't just timeout forwarded requests, time out the whole request,
-        //                   // be it cached, authorative or forwarded
-        //                   self.inner.set_timeout(ctx);
-        //               }
-        //               Ok(None) => debug!("0 bytes sent. Staying in same state {:?}", ctx.token),
-        //               Err(e) => {
-        //                   self.inner.error_with(format!("Failed to write to upstream_socket. {:?} {:?}",
-        //                                                 e,
-        //                                                 ctx.token))
-        //               }
-        //           }
-        //     },
-        //     None => {}
-        // }
+        debug_assert!(ctx.events.is_writable());
+        if self.write_buf.is_empty() {
+            // No EDNS0 OPT here: it exists to let a UDP response come back larger than 512 bytes
+            // without the server truncating it. TCP already frames a response of any size behind
+            // its 2-byte length prefix, so advertising a bigger UDP payload size over a
+            // TCP-carried query advertises nothing anyone downstream reads.
+            let query = self.inner.query_buf.as_slice();
+            self.write_buf.reserve(2 + query.len());
+            self.write_buf.push((query.len() >> 8) as u8);
+            self.write_buf.push(query.len() as u8);
+            self.write_buf.extend_from_slice(query);
+        }
+
+        match self.upstream_socket {
+            Some(ref sock) => {
+                match sock.try_write(&self.write_buf[self.write_pos..]) {
+                    Ok(Some(n)) => {
+                        self.write_pos += n;
+                        if self.write_pos >= self.write_buf.len() {
+                            self.inner.set_state(RequestState::Forwarded);
+                            self.inner.register_upstream(ctx, EventSet::readable(), sock);
+                            // TODO: No, don't just timeout forwarded requests, time out the whole request,
+                            // be it cached, authorative or forwarded
+                            self.inner.set_timeout(ctx);
+                        } else {
+                            debug!("Partial write of {} bytes. Staying in same state {:?}", n, ctx.token);
+                        }
+                    }
+                    Ok(None) => debug!("0 bytes sent. Staying in same state {:?}", ctx.token),
+                    Err(e) => {
+                        self.inner.error_with(format!("Failed to write to upstream_socket. {:?} {:?}",
+                                                      e,
+                                                      ctx.token))
+                    }
+                }
+            }
+            None => {}
+        }
     }
 
     fn receive(&mut self, ctx: &mut RequestContext) {
-        // assert!(ctx.events.is_readable());
-        // let mut buf = [0; 4096];
-        // match self.upstream_socket {
-        //     Some(ref sock) => {
-        //         match sock.recv_from(&mut buf) {
-        //             Ok(Some((count, addr))) => {
-        //                 debug!("Received {} bytes from {:?}", count, addr);
-        //                 trace!("{:#?}", DnsMessage::parse(&buf));
-        //                 self.inner.buffer_response(&buf, count);
-        //                 self.inner.clear_timeout(ctx);
-        //                 self.inner.set_state(RequestState::ResponseReceived);
-        //             }
-        //             Ok(None) => debug!("No data received on upstream_socket. {:?}", ctx.token),
-        //             Err(e) => {
-        //                 self.inner.error_with(format!("Receive failed on {:?}. {:?}", ctx.token, e));
-        //                 self.inner.clear_timeout(ctx);
-        //             }
-        //         }
-        //     },
-        //     None => {}
-        // }
+        assert!(ctx.events.is_readable());
+        let mut buf = [0; 4096];
+        match self.upstream_socket {
+            Some(ref sock) => {
+                match sock.try_read(&mut buf) {
+                    Ok(Some(count)) => {
+                        debug!("Received {} bytes from upstream", count);
+                        self.read_buf.extend_from_slice(&buf[..count]);
+                        self.try_complete_response(ctx);
+                    }
+                    Ok(None) => debug!("No data received on upstream_socket. {:?}", ctx.token),
+                    Err(e) => {
+                        self.inner.error_with(format!("Receive failed on {:?}. {:?}", ctx.token, e));
+                        self.inner.clear_timeout(ctx);
+                    }
+                }
+            }
+            None => {}
+        }
     }
 
-    pub fn send(&self, socket: &TcpStream) {
-        // match self.inner.response_buf {
-        //     Some(ref response) => {
-        //         info!("{:?} bytes to send", response.len());
-        //         match socket.send_to(&mut &response.as_slice(), &self.client_addr) {
-        //             Ok(n) => debug!("{:?} bytes sent to client. {:?}", n, self.client_addr),
-        //             Err(e) => error!("Failed to send. {:?} Error was {:?}", self.client_addr, e),
-        //         }
-        //     }
-        //     None => error!("Trying to send before a response has been buffered."),
-        // }
+    ///Once the 2-byte length prefix and the full message it describes have arrived, hand the
+    ///message off to the request base and move on. Anything left in `read_buf` beyond that is
+    ///not expected (one query per connection) and is discarded along with it.
+    fn try_complete_response(&mut self, ctx: &mut RequestContext) {
+        if self.expected_len.is_none() && self.read_buf.len() >= 2 {
+            let len = ((self.read_buf[0] as u16) << 8) | self.read_buf[1] as u16;
+            self.expected_len = Some(len);
+        }
+
+        if let Some(len) = self.expected_len {
+            let total = 2 + len as usize;
+            if self.read_buf.len() >= total {
+                let msg = &self.read_buf[2..total];
+                trace!("{:#?}", msg);
+                if self.is_valid_response(msg) {
+                    self.inner.buffer_response(msg, len as usize);
+                    self.inner.clear_timeout(ctx);
+                    self.inner.set_state(RequestState::ResponseReceived);
+                } else {
+                    warn!("Dropping spoofed or mismatched response on {:?}", ctx.token);
+                }
+                self.read_buf.clear();
+                self.expected_len = None;
+            }
+        }
     }
 
-}
\ No newline at end of file
+    ///Anti-spoofing, shared with `request::udp` via `request::common::is_valid_response`. On TCP
+    ///this is close to a formality - `peer_addr()` just echoes the address `TcpStream::connect`
+    ///already dialed, so the OS itself rules out a response from anywhere else. It's the TID and
+    ///qname checks, and the UDP path where the source address isn't pinned by a handshake, that
+    ///do the real anti-spoofing work.
+    fn is_valid_response(&self, response: &[u8]) -> bool {
+        let from = match self.upstream_socket {
+            Some(ref sock) => sock.peer_addr().ok(),
+            None => None,
+        };
+
+        match from {
+            Some(from) => common::is_valid_response(&self.inner.query_buf,
+                                                     response,
+                                                     from,
+                                                     self.inner.params.upstream_addr),
+            None => false,
+        }
+    }
+
+    ///Frames and sends the buffered response to the client, same as `forward()` does for the
+    ///upstream-bound query: build the 2-byte length prefix once into `send_buf`, then track
+    ///`send_pos` across calls so a partial `try_write` is resumed rather than silently dropping
+    ///the rest of the response. Returns `true` once the whole framed response has been written, so
+    ///the caller knows when it can stop waiting on writable events for the client socket.
+    pub fn send(&mut self, socket: &TcpStream) -> bool {
+        if self.send_buf.is_empty() {
+            let response = match self.inner.response_buf {
+                Some(ref response) => response,
+                None => {
+                    error!("Trying to send before a response has been buffered.");
+                    return false;
+                }
+            };
+
+            self.send_buf.reserve(2 + response.len());
+            self.send_buf.push((response.len() >> 8) as u8);
+            self.send_buf.push(response.len() as u8);
+            self.send_buf.extend_from_slice(response.as_slice());
+        }
+
+        match socket.try_write(&self.send_buf[self.send_pos..]) {
+            Ok(Some(n)) => {
+                self.send_pos += n;
+                if self.send_pos >= self.send_buf.len() {
+                    debug!("{:?} bytes sent to client. {:?}", self.send_pos, self.client_addr);
+                    true
+                } else {
+                    debug!("Partial write of {} bytes to client. {:?}", n, self.client_addr);
+                    false
+                }
+            }
+            Ok(None) => {
+                debug!("0 bytes sent to client. {:?}", self.client_addr);
+                false
+            }
+            Err(e) => {
+                error!("Failed to send. {:?} Error was {:?}", self.client_addr, e);
+                false
+            }
+        }
+    }
+
+}
@@ -0,0 +1,159 @@
+use mio::EventSet;
+use mio::udp::UdpSocket;
+use std::net::SocketAddr;
+use request::base::{RequestState, RequestBase, RequestParams};
+use request::base::IRequest;
+use request::common;
+use server_mio::RequestContext;
+
+///How many times to retry binding the upstream-facing socket if the OS hands us a port that
+///collides with one already in use. `UdpSocket::v4()` binds to an OS-chosen ephemeral port, so in
+///practice a collision is vanishingly rare - this loop is defense in depth, not the primary source
+///of randomization.
+const MAX_BIND_ATTEMPTS: u8 = 10;
+
+pub struct UdpRequest {
+    upstream_socket: Option<UdpSocket>,
+    pub client_addr: SocketAddr,
+    pub inner: RequestBase,
+}
+
+impl IRequest<UdpRequest> for UdpRequest {
+    fn new_with(client_addr: SocketAddr, request: RequestBase) -> UdpRequest {
+        return UdpRequest {
+            upstream_socket: None,
+            client_addr: client_addr,
+            inner: request,
+        };
+    }
+
+    fn base(&mut self) -> &mut RequestBase {
+        &mut self.inner
+    }
+}
+
+///Binds the upstream-facing socket to a fresh, OS-chosen ephemeral port (source port
+///randomization, RFC 5452 2.1), retrying on the rare chance bind() collides with a port already
+///in use.
+fn bind_upstream_socket() -> Option<UdpSocket> {
+    for attempt in 0..MAX_BIND_ATTEMPTS {
+        match UdpSocket::v4() {
+            Ok(sock) => return Some(sock),
+            Err(e) => warn!("Bind attempt {} for upstream socket failed. {:?}", attempt, e),
+        }
+    }
+    None
+}
+
+impl UdpRequest {
+
+    fn accept(&mut self, ctx: &mut RequestContext) {
+        debug_assert!(ctx.events.is_readable());
+        self.inner.set_state(RequestState::Accepted);
+
+        if common::try_resolve_recursively(&mut self.inner) {
+            return;
+        }
+
+        match bind_upstream_socket() {
+            Some(sock) => {
+                debug!("upstream udp socket bound");
+                self.upstream_socket = Some(sock);
+                match self.upstream_socket {
+                    Some(ref sock) => self.inner.register_upstream(ctx, EventSet::writable(), sock),
+                    None => error!("No upstream socket"),
+                }
+            }
+            None => {
+                self.inner.error_with(format!("Failed to bind an upstream socket after {} attempts",
+                                              MAX_BIND_ATTEMPTS))
+            }
+        }
+    }
+
+    pub fn ready(&mut self, ctx: &mut RequestContext) {
+        debug!("State {:?} {:?} {:?}",
+               self.inner.state,
+               ctx.token,
+               ctx.events);
+        match self.inner.state {
+            RequestState::New => self.accept(ctx),
+            RequestState::Accepted => self.forward(ctx),
+            RequestState::Forwarded => self.receive(ctx),
+            _ => debug!("Nothing to do for this state {:?}", self.inner.state),
+        }
+    }
+
+    fn forward(&mut self, ctx: &mut RequestContext) {
+        debug_assert!(ctx.events.is_writable());
+        common::append_edns0_opt(&mut self.inner.query_buf, self.inner.params.edns_max_payload_size);
+
+        match self.upstream_socket {
+            Some(ref sock) => {
+                match sock.send_to(&mut self.inner.query_buf.as_slice(), &self.inner.params.upstream_addr) {
+                    Ok(Some(_)) => {
+                        self.inner.set_state(RequestState::Forwarded);
+                        self.inner.register_upstream(ctx, EventSet::readable(), sock);
+                        // TODO: No, don't just timeout forwarded requests, time out the whole request,
+                        // be it cached, authorative or forwarded
+                        self.inner.set_timeout(ctx);
+                    }
+                    Ok(None) => debug!("0 bytes sent. Staying in same state {:?}", ctx.token),
+                    Err(e) => {
+                        self.inner.error_with(format!("Failed to write to upstream_socket. {:?} {:?}",
+                                                      e,
+                                                      ctx.token))
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn receive(&mut self, ctx: &mut RequestContext) {
+        assert!(ctx.events.is_readable());
+        let mut buf = [0; 4096];
+        match self.upstream_socket {
+            Some(ref sock) => {
+                match sock.recv_from(&mut buf) {
+                    Ok(Some((count, from))) => {
+                        debug!("Received {} bytes from {:?}", count, from);
+                        let msg = &buf[..count];
+                        trace!("{:#?}", msg);
+
+                        if common::is_valid_response(&self.inner.query_buf,
+                                                     msg,
+                                                     from,
+                                                     self.inner.params.upstream_addr) {
+                            self.inner.buffer_response(msg, count);
+                            self.inner.clear_timeout(ctx);
+                            self.inner.set_state(RequestState::ResponseReceived);
+                        } else {
+                            warn!("Dropping spoofed or mismatched response on {:?} from {:?}", ctx.token, from);
+                        }
+                    }
+                    Ok(None) => debug!("No data received on upstream_socket. {:?}", ctx.token),
+                    Err(e) => {
+                        self.inner.error_with(format!("Receive failed on {:?}. {:?}", ctx.token, e));
+                        self.inner.clear_timeout(ctx);
+                    }
+                }
+            }
+            None => {}
+        }
+    }
+
+    pub fn send(&self, socket: &UdpSocket) {
+        match self.inner.response_buf {
+            Some(ref response) => {
+                info!("{:?} bytes to send", response.len());
+                match socket.send_to(&mut &response.as_slice(), &self.client_addr) {
+                    Ok(n) => debug!("{:?} bytes sent to client. {:?}", n, self.client_addr),
+                    Err(e) => error!("Failed to send. {:?} Error was {:?}", self.client_addr, e),
+                }
+            }
+            None => error!("Trying to send before a response has been buffered."),
+        }
+    }
+
+}
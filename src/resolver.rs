@@ -0,0 +1,228 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::Duration;
+use dns::message::{DnsMessage, DnsName, DnsQuestion};
+use buf::{BufRead, DirectAccessBuf, SliceBuf};
+use cache::{Cache, CacheEntry, CacheKey};
+use request::common;
+
+const NS_TYPE: u16 = 2;
+const A_TYPE: u16 = 1;
+const IN_CLASS: u16 = 1;
+const DNS_PORT: u16 = 53;
+const RESOLVE_TIMEOUT_SECS: u64 = 5;
+
+///Stop following referrals after this many hops, in case of a referral loop between misconfigured
+///nameservers. A glueless nameserver lookup triggered along the way starts with the hops the
+///outer loop has left at that point; the hops it spends are not subtracted from the outer loop's
+///count - see `resolve_with`.
+pub const MAX_REFERRALS: u8 = 16;
+
+///IPv4 hints for the 13 root nameservers (a.root-servers.net .. m.root-servers.net), the starting
+///point for iterative resolution per RFC 1034 and chapter 5 of
+///https://github.com/EmilHernvall/dnsguide.
+const ROOT_SERVERS: [(u8, u8, u8, u8); 13] = [
+    (198, 41, 0, 4),
+    (199, 9, 14, 201),
+    (192, 33, 4, 12),
+    (199, 7, 91, 13),
+    (192, 203, 230, 10),
+    (192, 5, 5, 241),
+    (192, 112, 36, 4),
+    (198, 97, 190, 53),
+    (192, 36, 148, 17),
+    (192, 58, 128, 30),
+    (193, 0, 14, 129),
+    (199, 7, 83, 42),
+    (202, 12, 27, 33),
+];
+
+///Which strategy a request resolves a question with. Selected for the whole server by
+///`command_line`'s `--recursive` flag.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ResolverMode {
+    ///Forward the query as-is to a single fixed upstream (`RequestParams::upstream_addr`) and
+    ///trust its answer - what `TcpRequest`/`UdpRequest` do by default.
+    Forward,
+    ///Resolve from scratch via `RecursiveResolver`, walking referrals down from the root.
+    Recursive,
+}
+
+///Resolves a question from scratch, walking referrals down from the root rather than forwarding
+///to a single fixed upstream. Reuses one `Cache` across a resolution - each question (including
+///a glueless nameserver lookup along the way) is checked against it before any server is queried,
+///and every answer is fed back into it. Referral responses themselves are not cached.
+pub struct RecursiveResolver;
+
+impl RecursiveResolver {
+    pub fn new() -> RecursiveResolver {
+        RecursiveResolver
+    }
+
+    ///Resolve `question` using a private, empty `Cache`. Prefer `resolve_with` when resolving
+    ///many questions against the same resolver, so referrals and answers are actually reused.
+    pub fn resolve(&self, question: &DnsQuestion) -> Option<DnsMessage> {
+        let mut cache = Cache::default();
+        self.resolve_with(question, &mut cache, MAX_REFERRALS)
+    }
+
+    ///Resolve `question`, checking `cache` for it first and feeding the result back into it, and
+    ///never making more than `remaining` queries in this call's own referral-following loop. A
+    ///glueless nameserver lookup this resolution triggers gets the count left at that point as its
+    ///own budget; what it spends is not subtracted from this call's count.
+    pub fn resolve_with(&self, question: &DnsQuestion, cache: &mut Cache, remaining: u8) -> Option<DnsMessage> {
+        let key = CacheKey::from(question);
+        if let Some(entry) = cache.get(&key) {
+            debug!("Cache hit resolving {:?} (negative={}, rcode={})", question, entry.negative, entry.rcode);
+            let mut cached = DnsMessage::query_for(question);
+            cached.rcode = entry.rcode;
+            cached.answers = entry.answers;
+            return Some(cached);
+        }
+
+        let mut server = root_server(0);
+        let budget = remaining;
+        let mut remaining = remaining;
+
+        while remaining > 0 {
+            remaining -= 1;
+
+            let (response, raw) = match query(server, question) {
+                Some(result) => result,
+                None => return None,
+            };
+
+            if !response.answers.is_empty() {
+                if let Some(entry) = CacheEntry::from(&response) {
+                    cache.upsert(key, entry);
+                }
+                return Some(response);
+            }
+
+            match next_server(&response, &raw, self, cache, remaining) {
+                Some(addr) => server = addr,
+                None => {
+                    //Authoritative NXDOMAIN/NODATA, or a referral we couldn't use - nothing more
+                    //to follow. Cache it (negative, per the SOA MINIMUM) so the next lookup for
+                    //the same question is a cache hit rather than a fresh walk from the root.
+                    if let Some(entry) = CacheEntry::from(&response) {
+                        cache.upsert(key, entry);
+                    }
+                    return Some(response);
+                }
+            }
+        }
+
+        warn!("Gave up resolving {:?} after {} referrals", question, budget);
+        None
+    }
+}
+
+fn root_server(index: usize) -> SocketAddr {
+    let (a, b, c, d) = ROOT_SERVERS[index];
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, d)), DNS_PORT)
+}
+
+///Queries `server` and returns both the parsed response and the raw bytes it arrived in - the raw
+///bytes are needed afterwards to follow any compression pointer in a referral's NS rdata, since a
+///pointer target is an offset into the whole message, not into the rdata alone.
+///
+///This socket is never `connect`ed, so unlike `TcpRequest`'s stream (and like `UdpRequest`'s
+///upstream socket) nothing at the OS level ties it to `server` - any off-path attacker who
+///guesses the local port can inject a fake referral or answer. Validated the same way those two are, via
+///`request::common::is_valid_response` (address, transaction ID, and qname), before the response
+///is trusted.
+fn query(server: SocketAddr, question: &DnsQuestion) -> Option<(DnsMessage, Vec<u8>)> {
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(sock) => sock,
+        Err(e) => {
+            error!("Failed to bind resolver socket. {:?}", e);
+            return None;
+        }
+    };
+
+    if let Err(e) = socket.set_read_timeout(Some(Duration::from_secs(RESOLVE_TIMEOUT_SECS))) {
+        warn!("Failed to set resolver socket read timeout. {:?}", e);
+    }
+
+    let query = DnsMessage::query_for(question).to_bytes();
+    if let Err(e) = socket.send_to(&query, server) {
+        error!("Failed to send query to {:?}. {:?}", server, e);
+        return None;
+    }
+
+    let mut buf = [0; 4096];
+    match socket.recv_from(&mut buf) {
+        Ok((count, from)) => {
+            let raw = buf[..count].to_vec();
+            if !common::is_valid_response(&query, &raw, from, server) {
+                warn!("Dropping spoofed or mismatched response from {:?}, expected {:?}", from, server);
+                return None;
+            }
+            let response = DnsMessage::parse(&raw);
+            trace!("{:#?}", response);
+            Some((response, raw))
+        }
+        Err(e) => {
+            warn!("No response from {:?}. {:?}", server, e);
+            None
+        }
+    }
+}
+
+///A referral carries the delegated nameservers as NS records in AUTHORITY. Prefer their glue
+///A records in ADDITIONAL when present, otherwise resolve the nameserver's own name - with the
+///same `cache` as the resolution that found this referral, and the `remaining` count it has left
+///as that lookup's own budget.
+fn next_server(response: &DnsMessage,
+                raw: &[u8],
+                resolver: &RecursiveResolver,
+                cache: &mut Cache,
+                remaining: u8)
+                -> Option<SocketAddr> {
+    let ns_rr = match response.authority.iter().find(|rr| rr.atype == NS_TYPE) {
+        Some(ns) => ns,
+        None => return None,
+    };
+
+    let ns_name = match decode_name(raw, ns_rr.rdata_offset) {
+        Some(name) => name,
+        None => {
+            warn!("Could not decode NS name in referral, giving up on this branch");
+            return None;
+        }
+    };
+
+    let glue = response.additional.iter()
+        .find(|rr| rr.atype == A_TYPE && rr.name.to_string() == ns_name)
+        .and_then(|a| ipv4_from_rdata(&a.rdata));
+
+    if let Some(ip) = glue {
+        return Some(SocketAddr::new(IpAddr::V4(ip), DNS_PORT));
+    }
+
+    debug!("No glue for nameserver {}, resolving it with {} referrals left", ns_name, remaining);
+    let ns_question = DnsQuestion::new(DnsName::from_string(ns_name), A_TYPE, IN_CLASS);
+    resolver.resolve_with(&ns_question, cache, remaining)
+        .and_then(|msg| msg.answers.first().and_then(|a| ipv4_from_rdata(&a.rdata)))
+        .map(|ip| SocketAddr::new(IpAddr::V4(ip), DNS_PORT))
+}
+
+fn ipv4_from_rdata(rdata: &[u8]) -> Option<Ipv4Addr> {
+    if rdata.len() != 4 {
+        return None;
+    }
+    Some(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3]))
+}
+
+///Decodes a (possibly compressed) name living at `rdata_offset` in `raw`. A compression pointer
+///inside an NS record's rdata jumps to an offset in the *whole message*, not within the rdata
+///alone, so this has to seek a `SliceBuf` over the raw message itself - `DnsAnswer::rdata_offset`
+///(set by `DnsMessage::parse` to where this record's rdata begins) is what makes that possible -
+///and from there it reads the name with the pointer-following, loop-safe `BufRead::read_qname`.
+fn decode_name(raw: &[u8], rdata_offset: usize) -> Option<String> {
+    let mut buf = SliceBuf::new(raw);
+    if !buf.seek(rdata_offset) {
+        return None;
+    }
+    buf.read_qname().ok()
+}